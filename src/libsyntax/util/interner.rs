@@ -16,25 +16,74 @@ use ast::Name;
 use parse::token::InternedString;
 
 use std::borrow::Borrow;
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
 use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::error::Error;
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::ops::Deref;
 use std::rc::Rc;
+use std::u32;
 
+// Computes a hash for `value` using `hash_builder`. Interning and lookup
+// both call this to pick `value`'s slot in `map`, so they have to agree on it.
+fn make_hash<H: BuildHasher, T: Hash + ?Sized>(hash_builder: &H, value: &T) -> u64 {
+    let mut state = hash_builder.build_hasher();
+    value.hash(&mut state);
+    state.finish()
+}
+
+/// The interner is full: it already holds as many entries as the limit it
+/// was created with (`u32::MAX` by default, the most a `Name` can number
+/// without wrapping around and colliding with an existing entry).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InternError;
+
+impl fmt::Display for InternError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interner capacity exceeded")
+    }
+}
+
+impl Error for InternError {
+    fn description(&self) -> &str {
+        "interner capacity exceeded"
+    }
+}
+
+// `map` holds only `Name`s, keyed by the hash of the value they name; the
+// values themselves live in `vect`, which is the single source of truth.
+// This halves the memory cost of the interner (previously every value was
+// stored once in `vect` and once again as a key in `map`) without paying
+// for it with a fresh per-entry allocation: unlike a `HashMap<u64,
+// Vec<Name>>`, a flat `HashMap<u64, Name>` never allocates per intern, at
+// the cost of occasionally (and harmlessly, see `try_intern`) failing to
+// dedupe two values whose hashes genuinely collide.
 pub struct Interner<T> {
-    map: RefCell<HashMap<T, Name>>,
-    vect: RefCell<Vec<T> >,
+    map: RefCell<HashMap<u64, Name>>,
+    vect: RefCell<Vec<T>>,
+    hash_builder: RandomState,
+    limit: u32,
 }
 
 // when traits can extend traits, we should extend index<Name,T> to get []
 impl<T: Eq + Hash + Clone + 'static> Interner<T> {
     pub fn new() -> Interner<T> {
+        Interner::with_capacity(u32::MAX)
+    }
+
+    /// Create an interner that refuses to hold more than `limit` entries
+    /// (interned values and gensyms alike), returning `InternError` from
+    /// the `try_*` methods (and panicking from their infallible
+    /// counterparts) once it's full.
+    pub fn with_capacity(limit: u32) -> Interner<T> {
         Interner {
             map: RefCell::new(HashMap::new()),
             vect: RefCell::new(Vec::new()),
+            hash_builder: RandomState::new(),
+            limit: limit,
         }
     }
 
@@ -47,25 +96,49 @@ impl<T: Eq + Hash + Clone + 'static> Interner<T> {
     }
 
     pub fn intern(&self, val: T) -> Name {
-        let mut map = self.map.borrow_mut();
-        match (*map).get(&val) {
-            Some(&idx) => return idx,
-            None => (),
+        self.try_intern(val).expect("interner capacity exceeded")
+    }
+
+    pub fn try_intern(&self, val: T) -> Result<Name, InternError> {
+        let hash = make_hash(&self.hash_builder, &val);
+        if let Some(name) = self.lookup(hash, |v| *v == val) {
+            return Ok(name);
         }
 
-        let mut vect = self.vect.borrow_mut();
-        let new_idx = Name((*vect).len() as u32);
-        (*map).insert(val.clone(), new_idx);
-        (*vect).push(val);
-        new_idx
+        // Extend `vect` before touching `map` again, so the two
+        // `RefCell` borrows below never overlap.
+        let new_idx = {
+            let mut vect = self.vect.borrow_mut();
+            if vect.len() as u64 >= self.limit as u64 {
+                return Err(InternError);
+            }
+            let new_idx = Name(vect.len() as u32);
+            vect.push(val);
+            new_idx
+        };
+        // A genuine 64-bit hash collision between two distinct values is
+        // astronomically unlikely; if one ever happens, this simply
+        // overwrites the older value's `map` slot, so a later lookup for
+        // it re-interns a second, separate `Name` for the same value
+        // instead of finding the first. Cheap, and correct in the sense
+        // that both entries remain valid — just no longer deduplicated.
+        self.map.borrow_mut().insert(hash, new_idx);
+        Ok(new_idx)
     }
 
     pub fn gensym(&self, val: T) -> Name {
+        self.try_gensym(val).expect("interner capacity exceeded")
+    }
+
+    pub fn try_gensym(&self, val: T) -> Result<Name, InternError> {
         let mut vect = self.vect.borrow_mut();
+        if vect.len() as u64 >= self.limit as u64 {
+            return Err(InternError);
+        }
         let new_idx = Name((*vect).len() as u32);
         // leave out of .map to avoid colliding
         (*vect).push(val);
-        new_idx
+        Ok(new_idx)
     }
 
     pub fn get(&self, idx: Name) -> T {
@@ -80,10 +153,22 @@ impl<T: Eq + Hash + Clone + 'static> Interner<T> {
 
     pub fn find<Q: ?Sized>(&self, val: &Q) -> Option<Name>
     where T: Borrow<Q>, Q: Eq + Hash {
-        let map = self.map.borrow();
-        match (*map).get(val) {
-            Some(v) => Some(*v),
-            None => None,
+        let hash = make_hash(&self.hash_builder, val);
+        self.lookup(hash, |v| v.borrow() == val)
+    }
+
+    // Look up `hash`'s slot in `map`, if any, and confirm it really names
+    // `is_match` by dereferencing into `vect` (see `try_intern` for why
+    // this can occasionally miss on a genuine hash collision).
+    fn lookup<F: Fn(&T) -> bool>(&self, hash: u64, is_match: F) -> Option<Name> {
+        let name = match self.map.borrow().get(&hash) {
+            Some(&name) => name,
+            None => return None,
+        };
+        if is_match(&self.vect.borrow()[name.usize()]) {
+            Some(name)
+        } else {
+            None
         }
     }
 
@@ -91,21 +176,123 @@ impl<T: Eq + Hash + Clone + 'static> Interner<T> {
         *self.map.borrow_mut() = HashMap::new();
         *self.vect.borrow_mut() = Vec::new();
     }
+
+    /// Iterate over the `(Name, &T)` pairs held by this interner.
+    ///
+    /// Like any other access through the interner's `RefCell`s, the
+    /// returned iterator holds `vect` immutably borrowed for as long as it
+    /// lives: interning a new value (or otherwise mutating this interner)
+    /// while an `Iter` is alive will panic, exactly as a nested
+    /// `borrow_mut` would. Re-interning a value already present only
+    /// reads, and does not panic.
+    pub fn iter(&self) -> Iter<T> {
+        Iter { guard: self.vect.borrow(), idx: 0 }
+    }
+
+    /// Iterate over the interned values, without their `Name`s.
+    pub fn values(&self) -> Values<T> {
+        Values { iter: self.iter() }
+    }
+
+    /// Iterate over the `Name`s of the interned values, in interning order.
+    pub fn names(&self) -> Names<T> {
+        Names { iter: self.iter() }
+    }
+
+    /// Consume the interner, returning its values without re-cloning them.
+    pub fn drain(self) -> Vec<T> {
+        self.vect.into_inner()
+    }
+}
+
+pub struct Iter<'a, T: 'a> {
+    guard: Ref<'a, Vec<T>>,
+    idx: usize,
+}
+
+impl<'a, T: 'a> Iterator for Iter<'a, T> {
+    type Item = (Name, &'a T);
+
+    fn next(&mut self) -> Option<(Name, &'a T)> {
+        if self.idx >= self.guard.len() {
+            return None;
+        }
+        let name = Name(self.idx as u32);
+        // `guard` keeps `vect` immutably borrowed for as long as `self` is
+        // alive, so nothing can move or drop the element behind this
+        // pointer before `self` does; extending the reference to `'a` is
+        // sound.
+        let val = unsafe { &*(&self.guard[self.idx] as *const T) };
+        self.idx += 1;
+        Some((name, val))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.guard.len() - self.idx;
+        (remaining, Some(remaining))
+    }
+}
+
+pub struct Values<'a, T: 'a> {
+    iter: Iter<'a, T>,
+}
+
+impl<'a, T: 'a> Iterator for Values<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.iter.next().map(|(_, val)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+pub struct Names<'a, T: 'a> {
+    iter: Iter<'a, T>,
+}
+
+impl<'a, T: 'a> Iterator for Names<'a, T> {
+    type Item = Name;
+
+    fn next(&mut self) -> Option<Name> {
+        self.iter.next().map(|(name, _)| name)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
 /// A StrInterner differs from Interner<String> in that it accepts
 /// &str rather than Rc<String>, resulting in less allocation.
 pub struct StrInterner {
-    map: RefCell<HashMap<InternedString, Name>>,
+    map: RefCell<HashMap<u64, Name>>,
     vect: RefCell<Vec<InternedString>>,
+    // `reset` below replaces the whole table, so the hasher used to key
+    // it has to be swappable too; kept in a `RefCell` like everything else
+    // that `reset` touches.
+    hash_builder: RefCell<RandomState>,
+    limit: u32,
 }
 
 /// When traits can extend traits, we should extend index<Name,T> to get []
 impl StrInterner {
     pub fn new() -> StrInterner {
+        StrInterner::with_capacity(u32::MAX)
+    }
+
+    /// Create an interner that refuses to hold more than `limit` entries
+    /// (interned strings and gensyms alike), returning `InternError` from
+    /// the `try_*` methods (and panicking from their infallible
+    /// counterparts) once it's full.
+    pub fn with_capacity(limit: u32) -> StrInterner {
         StrInterner {
             map: RefCell::new(HashMap::new()),
             vect: RefCell::new(Vec::new()),
+            hash_builder: RefCell::new(RandomState::new()),
+            limit: limit,
         }
     }
 
@@ -116,24 +303,45 @@ impl StrInterner {
     }
 
     pub fn intern(&self, val: &str) -> Name {
-        let mut map = self.map.borrow_mut();
-        match map.get(val) {
-            Some(&idx) => return idx,
-            None => (),
+        self.try_intern(val).expect("interner capacity exceeded")
+    }
+
+    pub fn try_intern(&self, val: &str) -> Result<Name, InternError> {
+        let hash = make_hash(&*self.hash_builder.borrow(), val);
+        if let Some(name) = self.lookup(hash, |v| &**v == val) {
+            return Ok(name);
         }
 
-        let new_idx = Name(self.len() as u32);
-        let val = Rc::new(val.to_owned());
-        map.insert(val.clone(), new_idx);
-        self.vect.borrow_mut().push(val);
-        new_idx
+        // Extend `vect` before touching `map` again, so the two `RefCell`
+        // borrows below never overlap.
+        let new_idx = {
+            let mut vect = self.vect.borrow_mut();
+            if vect.len() as u64 >= self.limit as u64 {
+                return Err(InternError);
+            }
+            let new_idx = Name(vect.len() as u32);
+            vect.push(Rc::new(val.to_owned()));
+            new_idx
+        };
+        // See `Interner::try_intern` for why overwriting on a genuine hash
+        // collision here is acceptable.
+        self.map.borrow_mut().insert(hash, new_idx);
+        Ok(new_idx)
     }
 
     pub fn gensym(&self, val: &str) -> Name {
-        let new_idx = Name(self.len() as u32);
+        self.try_gensym(val).expect("interner capacity exceeded")
+    }
+
+    pub fn try_gensym(&self, val: &str) -> Result<Name, InternError> {
+        let mut vect = self.vect.borrow_mut();
+        if vect.len() as u64 >= self.limit as u64 {
+            return Err(InternError);
+        }
+        let new_idx = Name(vect.len() as u32);
         // leave out of .map to avoid colliding
-        self.vect.borrow_mut().push(Rc::new(val.to_owned()));
-        new_idx
+        vect.push(Rc::new(val.to_owned()));
+        Ok(new_idx)
     }
 
     // I want these gensyms to share name pointers
@@ -146,13 +354,21 @@ impl StrInterner {
 
     /// Create a gensym with the same name as an existing
     /// entry.
-    pub fn gensym_copy(&self, idx : Name) -> Name {
-        let new_idx = Name(self.len() as u32);
-        // leave out of map to avoid colliding
+    pub fn gensym_copy(&self, idx: Name) -> Name {
+        self.try_gensym_copy(idx).expect("interner capacity exceeded")
+    }
+
+    /// Fallible counterpart to `gensym_copy`; see `try_gensym`.
+    pub fn try_gensym_copy(&self, idx: Name) -> Result<Name, InternError> {
         let mut vect = self.vect.borrow_mut();
+        if vect.len() as u64 >= self.limit as u64 {
+            return Err(InternError);
+        }
+        let new_idx = Name(vect.len() as u32);
+        // leave out of map to avoid colliding
         let existing = (*vect)[idx.usize()].clone();
         vect.push(existing);
-        new_idx
+        Ok(new_idx)
     }
 
     pub fn get(&self, idx: Name) -> InternedString {
@@ -165,9 +381,22 @@ impl StrInterner {
 
     pub fn find<Q: ?Sized>(&self, val: &Q) -> Option<Name>
     where InternedString: Borrow<Q>, Q: Eq + Hash {
-        match (*self.map.borrow()).get(val) {
-            Some(v) => Some(*v),
-            None => None,
+        let hash = make_hash(&*self.hash_builder.borrow(), val);
+        self.lookup(hash, |v| v.borrow() == val)
+    }
+
+    // Look up `hash`'s slot in `map`, if any, and confirm it really names
+    // `is_match` by dereferencing into `vect` (see `try_intern` for why
+    // this can occasionally miss on a genuine hash collision).
+    fn lookup<F: Fn(&InternedString) -> bool>(&self, hash: u64, is_match: F) -> Option<Name> {
+        let name = match self.map.borrow().get(&hash) {
+            Some(&name) => name,
+            None => return None,
+        };
+        if is_match(&self.vect.borrow()[name.usize()]) {
+            Some(name)
+        } else {
+            None
         }
     }
 
@@ -177,9 +406,101 @@ impl StrInterner {
     }
 
     pub fn reset(&self, other: StrInterner) {
+        // `map`'s buckets are keyed by hashes taken with `other`'s
+        // `hash_builder`, so it has to come along for the swap too.
+        *self.hash_builder.borrow_mut() = other.hash_builder.into_inner();
         *self.map.borrow_mut() = other.map.into_inner();
         *self.vect.borrow_mut() = other.vect.into_inner();
     }
+
+    /// Iterate over the `(Name, &InternedString)` pairs held by this
+    /// interner.
+    ///
+    /// Like any other access through the interner's `RefCell`s, the
+    /// returned iterator holds `vect` immutably borrowed for as long as it
+    /// lives: interning a new string (or otherwise mutating this interner)
+    /// while a `StrInternerIter` is alive will panic, exactly as a nested
+    /// `borrow_mut` would. Re-interning a string already present only
+    /// reads, and does not panic.
+    pub fn iter(&self) -> StrInternerIter {
+        StrInternerIter { guard: self.vect.borrow(), idx: 0 }
+    }
+
+    /// Iterate over the interned strings, without their `Name`s.
+    pub fn values(&self) -> StrInternerValues {
+        StrInternerValues { iter: self.iter() }
+    }
+
+    /// Iterate over the `Name`s of the interned strings, in interning
+    /// order.
+    pub fn names(&self) -> StrInternerNames {
+        StrInternerNames { iter: self.iter() }
+    }
+
+    /// Consume the interner, returning its values without re-cloning them.
+    pub fn drain(self) -> Vec<InternedString> {
+        self.vect.into_inner()
+    }
+}
+
+pub struct StrInternerIter<'a> {
+    guard: Ref<'a, Vec<InternedString>>,
+    idx: usize,
+}
+
+impl<'a> Iterator for StrInternerIter<'a> {
+    type Item = (Name, &'a InternedString);
+
+    fn next(&mut self) -> Option<(Name, &'a InternedString)> {
+        if self.idx >= self.guard.len() {
+            return None;
+        }
+        let name = Name(self.idx as u32);
+        // `guard` keeps `vect` immutably borrowed for as long as `self` is
+        // alive, so nothing can move or drop the element behind this
+        // pointer before `self` does; extending the reference to `'a` is
+        // sound.
+        let val = unsafe { &*(&self.guard[self.idx] as *const InternedString) };
+        self.idx += 1;
+        Some((name, val))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.guard.len() - self.idx;
+        (remaining, Some(remaining))
+    }
+}
+
+pub struct StrInternerValues<'a> {
+    iter: StrInternerIter<'a>,
+}
+
+impl<'a> Iterator for StrInternerValues<'a> {
+    type Item = &'a InternedString;
+
+    fn next(&mut self) -> Option<&'a InternedString> {
+        self.iter.next().map(|(_, val)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+pub struct StrInternerNames<'a> {
+    iter: StrInternerIter<'a>,
+}
+
+impl<'a> Iterator for StrInternerNames<'a> {
+    type Item = Name;
+
+    fn next(&mut self) -> Option<Name> {
+        self.iter.next().map(|(name, _)| name)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
 }
 
 #[cfg(test)]
@@ -261,4 +582,125 @@ mod tests {
         assert_eq!(i.get(Name(3)), Rc::new("zebra".to_owned()));
         assert_eq!(i.get(Name(4)), Rc::new("dog".to_owned()));
     }
+
+    #[test]
+    fn interner_try_intern_reports_exhaustion() {
+        let i: Interner<Rc<String>> = Interner::with_capacity(2);
+        assert_eq!(i.try_intern(Rc::new("dog".to_owned())), Ok(Name(0)));
+        assert_eq!(i.try_intern(Rc::new("cat".to_owned())), Ok(Name(1)));
+        // table is full, but re-interning an existing value still succeeds:
+        assert_eq!(i.try_intern(Rc::new("dog".to_owned())), Ok(Name(0)));
+        assert_eq!(i.try_intern(Rc::new("bird".to_owned())), Err(InternError));
+        assert_eq!(i.try_gensym(Rc::new("bird".to_owned())), Err(InternError));
+    }
+
+    #[test]
+    fn try_intern_reports_exhaustion() {
+        let i: StrInterner = StrInterner::with_capacity(2);
+        assert_eq!(i.try_intern("dog"), Ok(Name(0)));
+        assert_eq!(i.try_intern("cat"), Ok(Name(1)));
+        // table is full, but re-interning an existing string still succeeds:
+        assert_eq!(i.try_intern("dog"), Ok(Name(0)));
+        assert_eq!(i.try_intern("bird"), Err(InternError));
+        assert_eq!(i.try_gensym("bird"), Err(InternError));
+    }
+
+    #[test]
+    fn with_capacity_zero_boundary() {
+        let i: Interner<Rc<String>> = Interner::with_capacity(0);
+        assert_eq!(i.try_intern(Rc::new("dog".to_owned())), Err(InternError));
+        assert_eq!(i.try_gensym(Rc::new("dog".to_owned())), Err(InternError));
+
+        let s: StrInterner = StrInterner::with_capacity(0);
+        assert_eq!(s.try_intern("dog"), Err(InternError));
+        assert_eq!(s.try_gensym("dog"), Err(InternError));
+    }
+
+    #[test]
+    fn interner_iter_values_names() {
+        let i: Interner<Rc<String>> = Interner::new();
+        i.intern(Rc::new("dog".to_owned()));
+        i.intern(Rc::new("cat".to_owned()));
+        // gensym'd entries have no map slot, but must still show up in
+        // iteration order:
+        i.gensym(Rc::new("zebra".to_owned()));
+
+        assert_eq!(i.iter().collect::<Vec<_>>(), vec![
+            (Name(0), &Rc::new("dog".to_owned())),
+            (Name(1), &Rc::new("cat".to_owned())),
+            (Name(2), &Rc::new("zebra".to_owned())),
+        ]);
+        assert_eq!(i.values().cloned().collect::<Vec<_>>(), vec![
+            Rc::new("dog".to_owned()),
+            Rc::new("cat".to_owned()),
+            Rc::new("zebra".to_owned()),
+        ]);
+        assert_eq!(i.names().collect::<Vec<_>>(), vec![Name(0), Name(1), Name(2)]);
+    }
+
+    #[test]
+    fn interner_drain() {
+        let i: Interner<Rc<String>> = Interner::new();
+        i.intern(Rc::new("dog".to_owned()));
+        i.intern(Rc::new("cat".to_owned()));
+        assert_eq!(i.drain(), vec![
+            Rc::new("dog".to_owned()),
+            Rc::new("cat".to_owned()),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn interner_iter_panics_on_concurrent_intern() {
+        let i: Interner<Rc<String>> = Interner::new();
+        i.intern(Rc::new("dog".to_owned()));
+        let _iter = i.iter();
+        // `_iter` still holds `vect` borrowed, so this must panic exactly
+        // like a nested `borrow_mut` would.
+        i.intern(Rc::new("cat".to_owned()));
+    }
+
+    #[test]
+    fn string_interner_iter_values_names() {
+        let i: StrInterner = StrInterner::new();
+        i.intern("dog");
+        i.intern("cat");
+        // gensym'd entries have no map slot, but must still show up in
+        // iteration order:
+        i.gensym("zebra");
+
+        assert_eq!(i.iter().collect::<Vec<_>>(), vec![
+            (Name(0), &Rc::new("dog".to_owned())),
+            (Name(1), &Rc::new("cat".to_owned())),
+            (Name(2), &Rc::new("zebra".to_owned())),
+        ]);
+        assert_eq!(i.values().cloned().collect::<Vec<_>>(), vec![
+            Rc::new("dog".to_owned()),
+            Rc::new("cat".to_owned()),
+            Rc::new("zebra".to_owned()),
+        ]);
+        assert_eq!(i.names().collect::<Vec<_>>(), vec![Name(0), Name(1), Name(2)]);
+    }
+
+    #[test]
+    fn string_interner_drain() {
+        let i: StrInterner = StrInterner::new();
+        i.intern("dog");
+        i.intern("cat");
+        assert_eq!(i.drain(), vec![
+            Rc::new("dog".to_owned()),
+            Rc::new("cat".to_owned()),
+        ]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn string_interner_iter_panics_on_concurrent_intern() {
+        let i: StrInterner = StrInterner::new();
+        i.intern("dog");
+        let _iter = i.iter();
+        // `_iter` still holds `vect` borrowed, so this must panic exactly
+        // like a nested `borrow_mut` would.
+        i.intern("cat");
+    }
 }